@@ -1,14 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error;
 use std::fmt;
 use std::fs::File;
 use std::io::{self, BufReader, BufRead};
+use std::path::{Path, PathBuf};
 use std::str;
 
 #[derive(Debug)]
 enum AsmError {
    InvalidInstruction,
    InvalidAddressing,
+   InvalidExpression,
    InvalidLabel,
    DuplicateSymbol,
    UnresolvedReference,
@@ -16,6 +18,8 @@ enum AsmError {
    ArgumentTooLarge(u16),
    BranchTargetOutOfRange(u16),
    LocationUnreachable(u16),
+   IncludeNotFound(String),
+   IncludeCycle(String),
 }
 
 #[derive(Debug,Copy,Clone)]
@@ -39,13 +43,32 @@ enum Instruction {
 
    // pseudo-instructions
    ORG,
-   WRD
+   WRD,
+   EQU,
+   INCLUDE,
 }
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Clone)]
+enum Expr {
+   Literal(u16),
+   Label(usize),
+   Neg(Box<Expr>),
+   Not(Box<Expr>),
+   Add(Box<Expr>, Box<Expr>),
+   Sub(Box<Expr>, Box<Expr>),
+   Mul(Box<Expr>, Box<Expr>),
+   Div(Box<Expr>, Box<Expr>),
+   And(Box<Expr>, Box<Expr>),
+   Or(Box<Expr>, Box<Expr>),
+   Shl(Box<Expr>, Box<Expr>),
+   Shr(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug,Clone)]
 enum Operand {
    Literal(u16),
    Label(usize),
+   Expr(Expr),
 }
 
 #[derive(Debug,Copy,Clone)]
@@ -65,24 +88,64 @@ enum Addressing {
    Assembler,
 }
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Clone)]
 struct Opcode {
    instruction: Instruction,
    operand: Operand,
    addressing: Addressing,
 }
 
+// Byte range of a token within the source line it came from, used to
+// underline the offending token in diagnostic output.
+#[derive(Debug, Copy, Clone)]
+struct Span {
+   start: usize,
+   end: usize,
+}
+
+impl Span {
+   fn of(line: &str, token: &str) -> Span {
+      let start = token.as_ptr() as usize - line.as_ptr() as usize;
+      Span { start, end: start + token.len() }
+   }
+}
+
+// An AsmError paired with the source line and token it was raised against,
+// so it can be rendered with a caret under the exact token.
+struct Diagnostic {
+   line_num: usize,
+   text: String,
+   span: Span,
+   error: AsmError,
+}
+
+impl Diagnostic {
+   fn print(&self, file_name: &str) {
+      eprintln!("{}({}): Error {}.", file_name, self.line_num, self.error);
+      eprintln!("    {}", self.text);
+      let width = (self.span.end - self.span.start).max(1);
+      eprintln!("    {}{}", " ".repeat(self.span.start), "^".repeat(width));
+   }
+}
+
 #[derive(Debug)]
 struct Line {
    num: usize,
    org: u16,
    op: Opcode,
+   text: String,
+   operand_span: Span,
 }
 
 #[derive(Debug)]
 struct SymbolTable {
    symbols: HashMap<String, usize>,
-   addresses: Vec<u16>,
+   addresses: Vec<Option<u16>>,
+   // Parallel to `addresses`: true for a label bound to a program address
+   // (ORG/INCLUDE/instruction labels), false for an EQU constant. Only
+   // address labels should slide when a later pass (e.g. auto-pool) shifts
+   // addresses around -- an EQU value is never an address to begin with.
+   is_address: Vec<bool>,
 }
 
 impl fmt::Display for AsmError {
@@ -90,6 +153,7 @@ impl fmt::Display for AsmError {
       match *self {
          AsmError::InvalidInstruction           => write!(f, "Invalid instruction"),
          AsmError::InvalidAddressing            => write!(f, "Invalid addressing mode"),
+         AsmError::InvalidExpression            => write!(f, "Invalid expression"),
          AsmError::InvalidLabel                 => write!(f, "Invalid label"),
          AsmError::DuplicateSymbol              => write!(f, "Duplicate symbol"),
          AsmError::UnresolvedReference          => write!(f, "Unresolved reference"),
@@ -97,6 +161,8 @@ impl fmt::Display for AsmError {
          AsmError::ArgumentTooLarge(arg)        => write!(f, "Argument @{:04o} too large", arg),
          AsmError::BranchTargetOutOfRange(arg)  => write!(f, "Branch target @{:04o} out of range", arg),
          AsmError::LocationUnreachable(arg)     => write!(f, "Location @{:04o} unreachable", arg),
+         AsmError::IncludeNotFound(ref path)    => write!(f, "Cannot open include file '{}'", path),
+         AsmError::IncludeCycle(ref path)       => write!(f, "Include cycle detected at '{}'", path),
       }
    }
 }
@@ -106,6 +172,7 @@ impl error::Error for AsmError {
       match *self {
          AsmError::InvalidInstruction           => "invalid instruction",
          AsmError::InvalidAddressing            => "invalid addressing mode",
+         AsmError::InvalidExpression            => "invalid expression",
          AsmError::InvalidLabel                 => "invalid label",
          AsmError::DuplicateSymbol              => "duplicate symbol",
          AsmError::UnresolvedReference          => "unresolved reference",
@@ -113,6 +180,8 @@ impl error::Error for AsmError {
          AsmError::ArgumentTooLarge(_)          => "argument too large",
          AsmError::BranchTargetOutOfRange(_)    => "branch target out of range",
          AsmError::LocationUnreachable(_)       => "location unreachable",
+         AsmError::IncludeNotFound(_)           => "cannot open include file",
+         AsmError::IncludeCycle(_)              => "include cycle detected",
       }
    }
 
@@ -127,7 +196,8 @@ impl SymbolTable {
    fn new() -> SymbolTable {
       SymbolTable {
          symbols: HashMap::<String, usize>::new(),
-         addresses: Vec::<u16>::new()
+         addresses: Vec::<Option<u16>>::new(),
+         is_address: Vec::<bool>::new(),
       }
    }
 
@@ -137,27 +207,28 @@ impl SymbolTable {
       } else {
          let index = self.symbols.len();
          self.symbols.insert(String::from(symbol), index);
-         self.addresses.push(u16::max_value());
+         self.addresses.push(None);
+         self.is_address.push(false);
          index
       }
    }
 
-   fn add_label(&mut self, symbol: &str, address: u16) -> Result<usize, ()> {
+   // `is_address` distinguishes a label bound to a program address (which
+   // must slide if a later pass shifts addresses around) from an EQU
+   // constant (an arbitrary value that happens to live in the same table).
+   fn add_label(&mut self, symbol: &str, value: u16, is_address: bool) -> Result<usize, ()> {
       let index = self.add_reference(symbol);
-      if self.addresses[index] != u16::max_value() {
+      if self.addresses[index].is_some() {
          Err(())
       } else {
-         self.addresses[index] = address;
+         self.addresses[index] = Some(value);
+         self.is_address[index] = is_address;
          Ok(index)
       }
    }
 
    fn resolve_reference(&self, label: usize) -> Option<u16> {
-      if label < self.addresses.len() && self.addresses[label] < u16::max_value() {
-         Some(self.addresses[label])
-      } else {
-         None
-      }
+      self.addresses.get(label).copied().flatten()
    }
 }
 
@@ -175,6 +246,8 @@ fn inst_is_assembler(inst: Instruction) -> bool {
    match inst {
       Instruction::WRD => true,
       Instruction::ORG => true,
+      Instruction::EQU => true,
+      Instruction::INCLUDE => true,
       _ => false
    }
 }
@@ -182,13 +255,16 @@ fn inst_is_assembler(inst: Instruction) -> bool {
 fn inst_from_string(s: &str) -> Option<Instruction> {
    // Do upper-case conversion on the stack
    let bytes = s.as_bytes();
-   if bytes.len() != 3 {
+   if bytes.is_empty() || bytes.len() > 7 {
       return None;
    }
 
-   let buf: [u8; 3] = [ bytes[0] & !0x20, bytes[1] & !0x20, bytes[2] & !0x20 ];
+   let mut buf = [0u8; 7];
+   for (i, &b) in bytes.iter().enumerate() {
+      buf[i] = b & !0x20;
+   }
    let upper = unsafe {
-      str::from_utf8_unchecked(&buf)
+      str::from_utf8_unchecked(&buf[..bytes.len()])
    };
 
    match upper {
@@ -209,6 +285,8 @@ fn inst_from_string(s: &str) -> Option<Instruction> {
       "HLT" => Some(Instruction::HLT),
       "ORG" => Some(Instruction::ORG),
       "WRD" => Some(Instruction::WRD),
+      "EQU" => Some(Instruction::EQU),
+      "INCLUDE" => Some(Instruction::INCLUDE),
       _ => None,
    }
 }
@@ -246,6 +324,8 @@ fn opcode_to_int(op: &Opcode) -> u16 {
       Instruction::HLT => 0o7400 + addr,
       Instruction::ORG => 0o0000,
       Instruction::WRD => 0o0000,
+      Instruction::EQU => 0o0000,
+      Instruction::INCLUDE => 0o0000,
    };
 
    inst
@@ -303,84 +383,379 @@ fn parse_number(text: &str) -> Option<u16> {
    if r > 0xffff { None } else { Some(r as u16) }
 }
 
+// Recursive-descent parser for operand expressions such as `LABEL+4`,
+// `BASE-1`, `@10*2` or `(FOO|1)<<2`. Standard precedence: | < & < shift <
+// +/- < */ < unary < atom. Leaf literals reuse parse_number()'s @/$/decimal
+// radix handling; leaf identifiers become forward symbol references, same
+// as parse_operand()'s bare-identifier case.
+struct ExprParser<'a> {
+   chars: Vec<char>,
+   pos: usize,
+   symbols: &'a mut SymbolTable,
+}
+
+impl<'a> ExprParser<'a> {
+   fn peek(&self) -> Option<char> {
+      self.chars.get(self.pos).cloned()
+   }
+
+   fn peek2(&self) -> Option<char> {
+      self.chars.get(self.pos + 1).cloned()
+   }
+
+   fn parse(&mut self) -> Result<Expr, AsmError> {
+      let e = self.parse_or()?;
+      if self.pos != self.chars.len() {
+         return Err(AsmError::InvalidExpression);
+      }
+      Ok(e)
+   }
+
+   fn parse_or(&mut self) -> Result<Expr, AsmError> {
+      let mut lhs = self.parse_and()?;
+      while self.peek() == Some('|') {
+         self.pos += 1;
+         let rhs = self.parse_and()?;
+         lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+      }
+      Ok(lhs)
+   }
+
+   fn parse_and(&mut self) -> Result<Expr, AsmError> {
+      let mut lhs = self.parse_shift()?;
+      while self.peek() == Some('&') {
+         self.pos += 1;
+         let rhs = self.parse_shift()?;
+         lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+      }
+      Ok(lhs)
+   }
+
+   fn parse_shift(&mut self) -> Result<Expr, AsmError> {
+      let mut lhs = self.parse_add()?;
+      loop {
+         if self.peek() == Some('<') && self.peek2() == Some('<') {
+            self.pos += 2;
+            let rhs = self.parse_add()?;
+            lhs = Expr::Shl(Box::new(lhs), Box::new(rhs));
+         } else if self.peek() == Some('>') && self.peek2() == Some('>') {
+            self.pos += 2;
+            let rhs = self.parse_add()?;
+            lhs = Expr::Shr(Box::new(lhs), Box::new(rhs));
+         } else {
+            break;
+         }
+      }
+      Ok(lhs)
+   }
+
+   fn parse_add(&mut self) -> Result<Expr, AsmError> {
+      let mut lhs = self.parse_mul()?;
+      loop {
+         match self.peek() {
+            Some('+') => {
+               self.pos += 1;
+               let rhs = self.parse_mul()?;
+               lhs = Expr::Add(Box::new(lhs), Box::new(rhs));
+            },
+            Some('-') => {
+               self.pos += 1;
+               let rhs = self.parse_mul()?;
+               lhs = Expr::Sub(Box::new(lhs), Box::new(rhs));
+            },
+            _ => break,
+         }
+      }
+      Ok(lhs)
+   }
+
+   fn parse_mul(&mut self) -> Result<Expr, AsmError> {
+      let mut lhs = self.parse_unary()?;
+      loop {
+         match self.peek() {
+            Some('*') => {
+               self.pos += 1;
+               let rhs = self.parse_unary()?;
+               lhs = Expr::Mul(Box::new(lhs), Box::new(rhs));
+            },
+            Some('/') => {
+               self.pos += 1;
+               let rhs = self.parse_unary()?;
+               lhs = Expr::Div(Box::new(lhs), Box::new(rhs));
+            },
+            _ => break,
+         }
+      }
+      Ok(lhs)
+   }
+
+   fn parse_unary(&mut self) -> Result<Expr, AsmError> {
+      match self.peek() {
+         Some('-') => {
+            self.pos += 1;
+            Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+         },
+         Some('~') => {
+            self.pos += 1;
+            Ok(Expr::Not(Box::new(self.parse_unary()?)))
+         },
+         _ => self.parse_atom(),
+      }
+   }
+
+   fn parse_atom(&mut self) -> Result<Expr, AsmError> {
+      if self.peek() == Some('(') {
+         self.pos += 1;
+         let e = self.parse_or()?;
+         if self.peek() != Some(')') {
+            return Err(AsmError::InvalidExpression);
+         }
+         self.pos += 1;
+         return Ok(e);
+      }
+
+      let start = self.pos;
+      while let Some(c) = self.peek() {
+         if c.is_alphanumeric() || c == '_' || c == '@' || c == '$' {
+            self.pos += 1;
+         } else {
+            break;
+         }
+      }
+
+      if self.pos == start {
+         return Err(AsmError::InvalidExpression);
+      }
+
+      let token: String = self.chars[start..self.pos].iter().collect();
+      if let Some(val) = parse_number(&token) {
+         Ok(Expr::Literal(val))
+      } else if string_is_identifier(&token) {
+         Ok(Expr::Label(self.symbols.add_reference(&token)))
+      } else {
+         Err(AsmError::InvalidLabel)
+      }
+   }
+}
+
+fn parse_expr(token: &str, symbols: &mut SymbolTable) -> Result<Expr, AsmError> {
+   let mut parser = ExprParser{ chars: token.chars().collect(), pos: 0, symbols };
+   parser.parse()
+}
+
+fn eval_expr(expr: &Expr, symbols: &SymbolTable) -> Result<u16, AsmError> {
+   match expr {
+      Expr::Literal(v) => Ok(*v),
+      Expr::Label(label) => symbols.resolve_reference(*label).ok_or(AsmError::UnresolvedReference),
+      Expr::Neg(a) => Ok(0u16.wrapping_sub(eval_expr(a, symbols)?)),
+      Expr::Not(a) => Ok(!eval_expr(a, symbols)?),
+      Expr::Add(a, b) => Ok(eval_expr(a, symbols)?.wrapping_add(eval_expr(b, symbols)?)),
+      Expr::Sub(a, b) => Ok(eval_expr(a, symbols)?.wrapping_sub(eval_expr(b, symbols)?)),
+      Expr::Mul(a, b) => Ok(eval_expr(a, symbols)?.wrapping_mul(eval_expr(b, symbols)?)),
+      Expr::Div(a, b) => {
+         let divisor = eval_expr(b, symbols)?;
+         if divisor == 0 { Ok(0) } else { Ok(eval_expr(a, symbols)?.wrapping_div(divisor)) }
+      },
+      Expr::And(a, b) => Ok(eval_expr(a, symbols)? & eval_expr(b, symbols)?),
+      Expr::Or(a, b) => Ok(eval_expr(a, symbols)? | eval_expr(b, symbols)?),
+      Expr::Shl(a, b) => Ok(eval_expr(a, symbols)?.wrapping_shl(eval_expr(b, symbols)? as u32)),
+      Expr::Shr(a, b) => Ok(eval_expr(a, symbols)?.wrapping_shr(eval_expr(b, symbols)? as u32)),
+   }
+}
+
 fn parse_operand(token: &str, symbols: &mut SymbolTable) -> Result<Operand, AsmError> {
    if let Some(val) = parse_number(token) {
       Ok(Operand::Literal(val as u16))
    } else if string_is_identifier(token) {
       Ok(Operand::Label(symbols.add_reference(token)))
    } else {
-      Err(AsmError::InvalidLabel)
+      Ok(Operand::Expr(parse_expr(token, symbols)?))
    }
 }
 
-fn parse_line(text: &str, address: u16, symbols: &mut SymbolTable) -> Result<Opcode, AsmError> {
+// Extracts the quoted path from an `INCLUDE "file"` line, along with its
+// leading label if any (so the caller can bind it to the include's address,
+// the same as any other label). Returns None for any other line, so the
+// caller can fall through to the normal parse_line() path.
+fn parse_include_path(text: &str) -> Option<(Option<(&str, Span)>, String)> {
    let mut tokens = text.split_whitespace();
 
-   let mut token = tokens.next().unwrap();
+   let mut token = tokens.next()?;
+   let label = if token.ends_with(':') {
+      let span = Span::of(text, token);
+      let name = &token[..token.len() - 1];
+      token = tokens.next()?;
+      Some((name, span))
+   } else {
+      None
+   };
+
+   if let Instruction::INCLUDE = inst_from_string(token)? {} else {
+      return None;
+   }
+
+   let path_token = tokens.next()?;
+   if path_token.len() >= 2 && path_token.starts_with('"') && path_token.ends_with('"') {
+      Some((label, String::from(&path_token[1..path_token.len() - 1])))
+   } else {
+      None
+   }
+}
 
-   // label
+// Span of the quoted path token on an `INCLUDE "file"` line, for pointing a
+// caret at it when the include itself fails (not found, or a cycle).
+fn parse_include_path_span(text: &str) -> Span {
+   let mut tokens = text.split_whitespace();
+   let mut token = tokens.next().unwrap_or(text);
    if token.ends_with(':') {
-      if !symbols.add_label(&token[..token.len()-1], address).is_ok() {
-         return Err(AsmError::DuplicateSymbol);
-      }
-      token = tokens.next().unwrap();
+      token = tokens.next().unwrap_or(token);
    }
+   let path_token = tokens.next().unwrap_or(token);
+   Span::of(text, path_token)
+}
+
+// Parses one source line into an Opcode, collecting every diagnostic it can
+// rather than stopping at the first: a duplicate label doesn't prevent the
+// operand from also being checked. Returns the operand's span (or the
+// mnemonic's, if there is no operand) alongside the Opcode so Line can keep
+// it around for assembly-time diagnostics (out-of-range arguments, etc).
+fn parse_line(text: &str, line_num: usize, address: u16, symbols: &mut SymbolTable) -> Result<(Opcode, Span), Vec<Diagnostic>> {
+   let mut errors = Vec::<Diagnostic>::new();
 
-   // instruction
-   let instruction;
-   match inst_from_string(token) {
-      Some(inst) => instruction = inst,
-      None => return Err(AsmError::InvalidInstruction),
+   let mut tokens = text.split_whitespace();
+   let mut token = tokens.next().unwrap();
+
+   // label (binding is deferred until the instruction is known, since EQU
+   // binds its label to the operand value rather than to `address`)
+   let label = if token.ends_with(':') {
+      let span = Span::of(text, token);
+      let name = &token[..token.len()-1];
+      token = tokens.next().unwrap();
+      Some((name, span))
+   } else {
+      None
+   };
+
+   let mnemonic_span = Span::of(text, token);
+   let instruction = match inst_from_string(token) {
+      Some(inst) => inst,
+      None => {
+         errors.push(Diagnostic{ line_num, text: String::from(text), span: mnemonic_span, error: AsmError::InvalidInstruction });
+         return Err(errors);
+      }
    };
 
    let op_token = tokens.next();
-   if op_token.is_none() {
+   let (opcode, operand_span) = if op_token.is_none() {
       let addressing = if inst_is_assembler(instruction) { Addressing::Assembler } else { Addressing::Immediate };
-      return Ok(Opcode{ instruction, operand: Operand::Literal(0), addressing });
-   }
-
-   token = op_token.unwrap();
-   
-   // operand
-   let operand;
-   let addressing;
-   if inst_is_assembler(instruction) {
-      // assembler (full-word)
-      addressing = Addressing::Assembler;
-      operand = parse_operand(token, symbols)?;
-   } else if token.starts_with('[') {
-      // indirect
-      if !token.ends_with(']') {
-         return Err(AsmError::MissingRightBracket);
-      }
-      addressing = if inst_is_jump(instruction) { Addressing::IndirectCurrentPage } else { Addressing::Indirect };
-      operand = parse_operand(&token[1..token.len()-1], symbols)?;
-    } else if token.starts_with('#') {
-      // immediate
-      addressing = Addressing::Immediate;
-      operand = parse_operand(&token[1..], symbols)?;
-   } else if token.starts_with("A+") || token.starts_with("a+") {
-      // accumulator
-      if !inst_is_jump(instruction) {
-         return Err(AsmError::InvalidAddressing);
-      }
-      addressing = Addressing::Accumulator;
-      operand = parse_operand(&token[2..], symbols)?;
+      (Opcode{ instruction, operand: Operand::Literal(0), addressing }, mnemonic_span)
    } else {
-      // direct/current page, possibly changing to zero-page once references are resolved
-      addressing = if inst_is_jump(instruction) { Addressing::Direct } else { Addressing::CurrentPage };
-      operand = parse_operand(token, symbols)?;
+      let token = op_token.unwrap();
+      let operand_span = Span::of(text, token);
+      let mut operand = Operand::Literal(0);
+      let mut addressing = Addressing::Immediate;
+
+      let result = if inst_is_assembler(instruction) {
+         // assembler (full-word)
+         addressing = Addressing::Assembler;
+         parse_operand(token, symbols)
+      } else if token.starts_with('[') {
+         // indirect
+         if !token.ends_with(']') {
+            Err(AsmError::MissingRightBracket)
+         } else {
+            addressing = if inst_is_jump(instruction) { Addressing::IndirectCurrentPage } else { Addressing::Indirect };
+            parse_operand(&token[1..token.len()-1], symbols)
+         }
+      } else if token.starts_with('#') {
+         // immediate
+         addressing = Addressing::Immediate;
+         parse_operand(&token[1..], symbols)
+      } else if token.starts_with("A+") || token.starts_with("a+") {
+         // accumulator
+         if !inst_is_jump(instruction) {
+            Err(AsmError::InvalidAddressing)
+         } else {
+            addressing = Addressing::Accumulator;
+            parse_operand(&token[2..], symbols)
+         }
+      } else {
+         // direct/current page, possibly changing to zero-page once references are resolved
+         addressing = if inst_is_jump(instruction) { Addressing::Direct } else { Addressing::CurrentPage };
+         parse_operand(token, symbols)
+      };
+
+      match result {
+         Ok(o) => operand = o,
+         Err(e) => errors.push(Diagnostic{ line_num, text: String::from(text), span: operand_span, error: e }),
+      }
+
+      (Opcode{ instruction, operand, addressing }, operand_span)
+   };
+
+   if let Instruction::EQU = instruction {
+      match label {
+         Some((name, label_span)) => {
+            let value = match &opcode.operand {
+               Operand::Literal(v) => Ok(*v),
+               Operand::Label(idx) => symbols.resolve_reference(*idx).ok_or(AsmError::UnresolvedReference),
+               Operand::Expr(e) => eval_expr(e, symbols),
+            };
+            match value {
+               Ok(v) => {
+                  if !symbols.add_label(name, v, false).is_ok() {
+                     errors.push(Diagnostic{ line_num, text: String::from(text), span: label_span, error: AsmError::DuplicateSymbol });
+                  }
+               },
+               Err(e) => errors.push(Diagnostic{ line_num, text: String::from(text), span: operand_span, error: e }),
+            }
+         },
+         None => errors.push(Diagnostic{ line_num, text: String::from(text), span: mnemonic_span, error: AsmError::InvalidLabel }),
+      }
+   } else if let Some((name, label_span)) = label {
+      if !symbols.add_label(name, address, true).is_ok() {
+         errors.push(Diagnostic{ line_num, text: String::from(text), span: label_span, error: AsmError::DuplicateSymbol });
+      }
    }
 
-   Ok(Opcode{ instruction, operand, addressing })
+   if errors.is_empty() {
+      Ok((opcode, operand_span))
+   } else {
+      Err(errors)
+   }
 }
 
-fn parse_lines<R>(file_name: &str, reader: R, symbols: &mut SymbolTable) -> Option<Vec<Line>>
+// Recursively assembles `path` at the current address, appending its lines
+// to `lines` in place. `path` is resolved relative to `including_dir` (the
+// directory of the file that contains the INCLUDE), not the process's
+// current directory, so a file can include a sibling by relative path
+// regardless of where the assembler was invoked from. `included` tracks the
+// canonicalized paths of files currently being included, so a file that
+// (directly or indirectly) includes itself is rejected instead of recursing
+// forever.
+fn parse_include(path: &str, including_dir: &Path, symbols: &mut SymbolTable, address: &mut u16,
+                  lines: &mut Vec<Line>, included: &mut HashSet<PathBuf>) -> Result<bool, AsmError> {
+   let resolved = including_dir.join(path);
+   let canon = resolved.canonicalize().map_err(|_| AsmError::IncludeNotFound(String::from(path)))?;
+   if !included.insert(canon.clone()) {
+      return Err(AsmError::IncludeCycle(String::from(path)));
+   }
+
+   let f = File::open(&canon).map_err(|_| AsmError::IncludeNotFound(String::from(path)))?;
+   let file_name = resolved.to_string_lossy().into_owned();
+   let dir = canon.parent().unwrap_or(Path::new("")).to_path_buf();
+   let success = parse_lines(&file_name, BufReader::new(f), &dir, symbols, address, lines, included);
+
+   included.remove(&canon);
+
+   Ok(success)
+}
+
+fn parse_lines<R>(file_name: &str, reader: R, dir: &Path, symbols: &mut SymbolTable, address: &mut u16,
+                   lines: &mut Vec<Line>, included: &mut HashSet<PathBuf>) -> bool
    where R: BufRead
 {
    let mut line_num = 0usize;
-   let mut address = 0u16;
-   let mut lines = Vec::<Line>::new();
    let mut success = true;
 
    for t in reader.lines() {
@@ -389,45 +764,255 @@ fn parse_lines<R>(file_name: &str, reader: R, symbols: &mut SymbolTable) -> Opti
       let text = t.unwrap();
       let text = text.split(';').next().unwrap().trim();
 
-      if !text.is_empty() {
-         let op;
-         match parse_line(&text, address, symbols) {
-            Ok(o) => op = o,
+      if text.is_empty() {
+         continue;
+      }
+
+      if let Some((label, path)) = parse_include_path(&text) {
+         if let Some((name, label_span)) = label {
+            if !symbols.add_label(name, *address, true).is_ok() {
+               Diagnostic{ line_num, text: String::from(text), span: label_span, error: AsmError::DuplicateSymbol }.print(file_name);
+               success = false;
+            }
+         }
+         match parse_include(&path, dir, symbols, address, lines, included) {
+            Ok(true) => (),
+            Ok(false) => success = false,
             Err(e) => {
-               eprintln!("{}({}): Error {}.", file_name, line_num, e);
+               let span = parse_include_path_span(&text);
+               Diagnostic{ line_num, text: String::from(text), span, error: e }.print(file_name);
                success = false;
-               continue;
             }
          }
+         continue;
+      }
 
-         if let Instruction::ORG = op.instruction {
-            address = match op.operand {
-               Operand::Literal(o) => o,
-               _ => address,
+      let (op, operand_span) = match parse_line(&text, line_num, *address, symbols) {
+         Ok(result) => result,
+         Err(diagnostics) => {
+            for d in &diagnostics {
+               d.print(file_name);
             }
-         } else {
-            lines.push(Line{num: line_num, org: address, op});
-            address += 1;
+            success = false;
+            continue;
+         }
+      };
+
+      match op.instruction {
+         Instruction::ORG => {
+            match resolve_operand(&op.operand, symbols) {
+               Ok(o) => *address = o,
+               Err(e) => {
+                  Diagnostic{ line_num, text: String::from(text), span: operand_span, error: e }.print(file_name);
+                  success = false;
+               }
+            }
+         },
+         Instruction::EQU => (),
+         _ => {
+            lines.push(Line{num: line_num, org: *address, op, text: String::from(text), operand_span});
+            *address += 1;
+         }
+      }
+   }
+
+   success
+}
+
+// Returns the page number of `addr` (the address with its 6-bit in-page
+// offset shifted off). A CurrentPage/Direct reference is reachable only when
+// its target shares the referencing line's page number.
+fn page_of(addr: u16) -> u16 {
+   addr >> 6
+}
+
+// True if `op`, sitting at `org`, reaches outside its own page (a
+// CurrentPage reference into page 0 is not counted, since it assembles as
+// ZeroPage). Only
+// CurrentPage and Direct are eligible for auto-pool rewriting: Indirect and
+// IndirectCurrentPage are already an explicit indirection, and this ISA has
+// no double-indirect form to fall back to.
+fn needs_auto_pool(op: &Opcode, org: u16, arg: u16) -> bool {
+   match op.addressing {
+      Addressing::CurrentPage => page_of(arg) != 0 && page_of(arg) != page_of(org),
+      Addressing::Direct => page_of(arg) != page_of(org),
+      _ => false,
+   }
+}
+
+// Lays `lines` out in address order, inserting one WRD pool word per distinct
+// out-of-range target on each page that needs one. Pool words for a page are
+// placed immediately after the last line belonging to that page (the page
+// boundary), using each line's *incoming* org to group it into a page -- this
+// stays stable within one layout pass even though addresses shift as earlier
+// pages grow.
+//
+// Returns, aligned with `lines`: the new org of every original line, an
+// old-org -> new-org map (used to slide symbol addresses along with the
+// lines they label), and the fully materialized output (originals, with
+// pooled ones rewritten to Indirect/IndirectCurrentPage, interleaved with the
+// synthesized WRD pool words).
+fn layout_auto_pool(lines: &[Line], pooled: &HashSet<usize>, args: &[u16]) -> (Vec<u16>, HashMap<u16, u16>, Vec<Line>) {
+   // Iterated in index order rather than HashSet's hash order, so pool
+   // layout (and the resulting machine code) is deterministic run to run.
+   let mut pooled_sorted: Vec<usize> = pooled.iter().copied().collect();
+   pooled_sorted.sort_unstable();
+
+   let mut page_pool = HashMap::<u16, Vec<u16>>::new();
+   for i in pooled_sorted {
+      let page = page_of(lines[i].org);
+      let targets = page_pool.entry(page).or_insert_with(Vec::new);
+      if !targets.contains(&args[i]) {
+         targets.push(args[i]);
+      }
+   }
+
+   let mut out = Vec::<Line>::with_capacity(lines.len());
+   let mut new_org = vec![0u16; lines.len()];
+   let mut remap = HashMap::<u16, u16>::new();
+   let mut addr = if lines.is_empty() { 0 } else { lines[0].org };
+   let mut current_page: Option<u16> = None;
+   // Indices (into `out`) of pooled instructions on the page currently being
+   // built, along with the target each needs a slot for. The pool for a page
+   // isn't placed until the page is flushed, so these are patched with their
+   // final slot address only once that happens.
+   let mut pending_patch = Vec::<(usize, u16)>::new();
+
+   fn flush(page: u16, addr: &mut u16, page_pool: &HashMap<u16, Vec<u16>>, out: &mut Vec<Line>, pending_patch: &mut Vec<(usize, u16)>) {
+      if let Some(targets) = page_pool.get(&page) {
+         let mut slot_of = HashMap::<u16, u16>::new();
+         for &target in targets {
+            slot_of.insert(target, *addr);
+            let text = format!("WRD @{:04o}", target);
+            let operand_span = Span::of(&text, &text[4..]);
+            out.push(Line {
+               num: 0,
+               org: *addr,
+               op: Opcode { instruction: Instruction::WRD, operand: Operand::Literal(target), addressing: Addressing::Assembler },
+               text,
+               operand_span,
+            });
+            *addr += 1;
+         }
+         for &(idx, target) in pending_patch.iter() {
+            out[idx].op.operand = Operand::Literal(slot_of[&target]);
          }
       }
+      pending_patch.clear();
    }
 
-   if success { Some(lines) } else { None }
+   for (i, l) in lines.iter().enumerate() {
+      let page = page_of(l.org);
+      if current_page.is_some() && current_page != Some(page) {
+         flush(current_page.unwrap(), &mut addr, &page_pool, &mut out, &mut pending_patch);
+      }
+      current_page = Some(page);
+
+      if i > 0 && l.org != lines[i - 1].org + 1 {
+         // An explicit ORG jump always keeps the address it was given.
+         addr = l.org;
+      }
+
+      remap.insert(l.org, addr);
+      new_org[i] = addr;
+
+      let mut new_line = Line { num: l.num, org: addr, op: l.op.clone(), text: l.text.clone(), operand_span: l.operand_span };
+      if pooled.contains(&i) {
+         new_line.op.addressing = match l.op.addressing {
+            Addressing::CurrentPage => Addressing::Indirect,
+            Addressing::Direct => Addressing::IndirectCurrentPage,
+            other => other,
+         };
+         pending_patch.push((out.len(), args[i]));
+      }
+      out.push(new_line);
+
+      addr += 1;
+   }
+   if let Some(page) = current_page {
+      flush(page, &mut addr, &page_pool, &mut out, &mut pending_patch);
+   }
+
+   (new_org, remap, out)
 }
 
-fn assemble_line(l: &Line, symbols: &SymbolTable) -> Result<(), AsmError> {
-   let mut op = l.op;
-   let arg;
-   match op.operand {
-      Operand::Literal(lit) => arg = lit,
-      Operand::Label(label) => {
-         let refr = symbols.resolve_reference(label);
-         if refr.is_none() {
-            return Err(AsmError::UnresolvedReference);
+// Opt-in pass (the `--auto-pool` flag) that rewrites out-of-range
+// CurrentPage/Direct references into Indirect/IndirectCurrentPage references
+// through a per-page literal pool, instead of failing assembly with
+// LocationUnreachable/BranchTargetOutOfRange. Inserting a pool word shifts
+// every later address, which can itself push a previously-fine reference out
+// of range (or bring a pooled one back in range), so layout is repeated to a
+// fixed point, for at most 32 passes, before the result is handed back for
+// assembly; a reference still out of range after the last pass is reported
+// by assembly as usual.
+//
+// Limitation: a page already packed with instructions may have no room left
+// for its pool word; in that case the pool word lands on the next page and
+// assembly reports LocationUnreachable/BranchTargetOutOfRange against the
+// pool word's address.
+fn apply_auto_pool(lines: Vec<Line>, symbols: &mut SymbolTable) -> Result<Vec<Line>, Vec<Diagnostic>> {
+   let mut current = lines;
+   let mut pooled = HashSet::<usize>::new();
+   let mut final_lines = Vec::new();
+
+   for _ in 0..32 {
+      let mut args = Vec::with_capacity(current.len());
+      for l in &current {
+         match resolve_operand(&l.op.operand, symbols) {
+            Ok(v) => args.push(v),
+            Err(e) => return Err(vec![Diagnostic{ line_num: l.num, text: l.text.clone(), span: l.operand_span, error: e }]),
          }
-         arg = refr.unwrap();
-      },
-   };
+      }
+
+      let mut grew = false;
+      for (i, l) in current.iter().enumerate() {
+         if !pooled.contains(&i) && needs_auto_pool(&l.op, l.org, args[i]) {
+            pooled.insert(i);
+            grew = true;
+         }
+      }
+
+      let (new_org, remap, materialized) = layout_auto_pool(&current, &pooled, &args);
+      for (addr, &is_address) in symbols.addresses.iter_mut().zip(symbols.is_address.iter()) {
+         if !is_address {
+            continue;
+         }
+         if let Some(a) = addr {
+            if let Some(&na) = remap.get(a) {
+               *a = na;
+            }
+         }
+      }
+      for (i, l) in current.iter_mut().enumerate() {
+         l.org = new_org[i];
+      }
+      final_lines = materialized;
+
+      if !grew {
+         break;
+      }
+   }
+
+   Ok(final_lines)
+}
+
+// Resolves an operand to its final numeric value, without applying any
+// addressing-mode rules. Shared by assemble_line() below and the auto-pool
+// pass above, which both need the raw value before addressing is settled.
+fn resolve_operand(operand: &Operand, symbols: &SymbolTable) -> Result<u16, AsmError> {
+   match operand {
+      Operand::Literal(lit) => Ok(*lit),
+      Operand::Label(label) => symbols.resolve_reference(*label).ok_or(AsmError::UnresolvedReference),
+      Operand::Expr(e) => eval_expr(e, symbols),
+   }
+}
+
+// Resolves the final addressing mode and machine word for a single line,
+// without performing any I/O. This is the core of the assembler and is
+// shared by assemble_words() and the listing printer below.
+fn assemble_line(l: &Line, symbols: &SymbolTable) -> Result<(Opcode, u16, u16), AsmError> {
+   let mut op = l.op.clone();
+   let arg = resolve_operand(&op.operand, symbols)?;
 
    match op.addressing {
       Addressing::Immediate | Addressing::Accumulator => {
@@ -462,9 +1047,42 @@ fn assemble_line(l: &Line, symbols: &SymbolTable) -> Result<(), AsmError> {
       _ => (),
    }
 
-   let opcode = if inst_is_assembler(op.instruction) { arg } else { opcode_to_int(&op) + (arg & 63) };
+   let word = if inst_is_assembler(op.instruction) { arg } else { opcode_to_int(&op) + (arg & 63) };
+
+   Ok((op, arg, word))
+}
+
+// Assembles `lines` into machine words in address order, honoring gaps left
+// by ORG. This is the library entry point: it has no knowledge of output
+// format and can feed an emulator or test harness directly. On failure,
+// returns a Diagnostic for every failing line rather than stopping at the
+// first.
+fn assemble_words(lines: &[Line], symbols: &SymbolTable) -> Result<Vec<u16>, Vec<Diagnostic>> {
+   let mut errors = Vec::new();
+   let mut resolved = Vec::<(u16, u16)>::new();
+
+   for l in lines {
+      match assemble_line(l, symbols) {
+         Ok((_, _, word)) => resolved.push((l.org, word)),
+         Err(e) => errors.push(Diagnostic{ line_num: l.num, text: l.text.clone(), span: l.operand_span, error: e }),
+      }
+   }
+
+   if !errors.is_empty() {
+      return Err(errors);
+   }
+
+   let top = resolved.iter().map(|&(org, _)| org).max().unwrap_or(0);
+   let mut words = vec![0u16; top as usize + 1];
+   for (org, word) in resolved {
+      words[org as usize] = word;
+   }
+
+   Ok(words)
+}
 
-   let name = match op.instruction {
+fn opcode_name(inst: Instruction) -> &'static str {
+   match inst {
       Instruction::ADD => "ADD",
       Instruction::SUB => "SUB",
       Instruction::RSB => "RSB",
@@ -482,29 +1100,33 @@ fn assemble_line(l: &Line, symbols: &SymbolTable) -> Result<(), AsmError> {
       Instruction::HLT => "HLT",
       Instruction::ORG => "ORG",
       Instruction::WRD => "WRD",
-   };
+      Instruction::EQU => "EQU",
+      Instruction::INCLUDE => "INCLUDE",
+   }
+}
+
+fn write_listing_line<W: io::Write>(out: &mut W, l: &Line, op: &Opcode, arg: u16, word: u16) -> io::Result<()> {
+   let name = opcode_name(op.instruction);
 
    match op.addressing {
-      Addressing::Immediate           => println!("{:04o}: {:04o} ; {} #{:02}",    l.org, opcode, name, arg),
-      Addressing::Accumulator         => println!("{:04o}: {:04o} ; {} A+{:02o}",  l.org, opcode, name, arg),
-      Addressing::ZeroPage            => println!("{:04o}: {:04o} ; {} @{:02o}",   l.org, opcode, name, arg),
-      Addressing::Indirect            => println!("{:04o}: {:04o} ; {} [@{:04o}]", l.org, opcode, name, arg),
-      Addressing::IndirectCurrentPage => println!("{:04o}: {:04o} ; {} [@{:04o}]", l.org, opcode, name, arg),
-      Addressing::IndirectZeroPage    => println!("{:04o}: {:04o} ; {} [@{:02o}]", l.org, opcode, name, arg),
-      _                               => println!("{:04o}: {:04o} ; {} @{:04o}",   l.org, opcode, name, arg),
+      Addressing::Immediate           => writeln!(out, "{:04o}: {:04o} ; {} #{:02}",    l.org, word, name, arg),
+      Addressing::Accumulator         => writeln!(out, "{:04o}: {:04o} ; {} A+{:02o}",  l.org, word, name, arg),
+      Addressing::ZeroPage            => writeln!(out, "{:04o}: {:04o} ; {} @{:02o}",   l.org, word, name, arg),
+      Addressing::Indirect            => writeln!(out, "{:04o}: {:04o} ; {} [@{:04o}]", l.org, word, name, arg),
+      Addressing::IndirectCurrentPage => writeln!(out, "{:04o}: {:04o} ; {} [@{:04o}]", l.org, word, name, arg),
+      Addressing::IndirectZeroPage    => writeln!(out, "{:04o}: {:04o} ; {} [@{:02o}]", l.org, word, name, arg),
+      _                               => writeln!(out, "{:04o}: {:04o} ; {} @{:04o}",   l.org, word, name, arg),
    }
-
-   Ok(())
 }
 
-fn assemble_file(file_name: &str, lines: &Vec<Line>, symbols: &SymbolTable) -> bool {
+fn assemble_file<W: io::Write>(file_name: &str, lines: &Vec<Line>, symbols: &SymbolTable, out: &mut W) -> bool {
    let mut success = true;
 
    for l in lines {
       match assemble_line(l, symbols) {
-         Ok(_) => (),
+         Ok((op, arg, word)) => write_listing_line(out, l, &op, arg, word).unwrap(),
          Err(e) => {
-            eprintln!("{}({}): Error {}.", file_name, l.num, e);
+            Diagnostic{ line_num: l.num, text: l.text.clone(), span: l.operand_span, error: e }.print(file_name);
             success = false;
          }
       }
@@ -513,28 +1135,260 @@ fn assemble_file(file_name: &str, lines: &Vec<Line>, symbols: &SymbolTable) -> b
    success
 }
 
-fn parse_file(name: Option<String>) -> bool {
+// Selects the output format. Listing is printed line by line from
+// assemble_line() results; the other formats are built from assemble_words().
+#[derive(Debug, Copy, Clone)]
+enum OutputFormat {
+   Listing,
+   Binary { little_endian: bool },
+   IntelHex,
+   Logisim,
+}
+
+fn format_from_string(s: &str) -> Option<OutputFormat> {
+   match s {
+      "listing"       => Some(OutputFormat::Listing),
+      "bin" | "bin-be" => Some(OutputFormat::Binary { little_endian: false }),
+      "bin-le"        => Some(OutputFormat::Binary { little_endian: true }),
+      "hex" | "ihex"  => Some(OutputFormat::IntelHex),
+      "logisim"       => Some(OutputFormat::Logisim),
+      _ => None,
+   }
+}
+
+fn write_binary<W: io::Write>(words: &[u16], little_endian: bool, out: &mut W) -> io::Result<()> {
+   for &w in words {
+      if little_endian {
+         out.write_all(&w.to_le_bytes())?;
+      } else {
+         out.write_all(&w.to_be_bytes())?;
+      }
+   }
+
+   Ok(())
+}
+
+fn write_intel_hex_record<W: io::Write>(out: &mut W, addr: u16, rec_type: u8, data: &[u8]) -> io::Result<()> {
+   let mut sum = data.len() as u8;
+   sum = sum.wrapping_add((addr >> 8) as u8);
+   sum = sum.wrapping_add((addr & 0xff) as u8);
+   sum = sum.wrapping_add(rec_type);
+   for &b in data {
+      sum = sum.wrapping_add(b);
+   }
+   let checksum = (!sum).wrapping_add(1);
+
+   write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, rec_type)?;
+   for &b in data {
+      write!(out, "{:02X}", b)?;
+   }
+   writeln!(out, "{:02X}", checksum)
+}
+
+// Intel HEX stores 16-bit words as two big-endian bytes at byte addresses,
+// eight words (16 bytes) per data record, followed by the EOF record.
+fn write_intel_hex<W: io::Write>(words: &[u16], out: &mut W) -> io::Result<()> {
+   for (i, chunk) in words.chunks(8).enumerate() {
+      let addr = (i * 16) as u16;
+      let mut data = Vec::<u8>::with_capacity(chunk.len() * 2);
+      for &w in chunk {
+         data.push((w >> 8) as u8);
+         data.push((w & 0xff) as u8);
+      }
+      write_intel_hex_record(out, addr, 0x00, &data)?;
+   }
+
+   write_intel_hex_record(out, 0, 0x01, &[])
+}
+
+// Logisim/digital "v2.0 raw" memory image: one hex word per line, with
+// repeated runs compressed as `count*value`.
+fn write_logisim<W: io::Write>(words: &[u16], out: &mut W) -> io::Result<()> {
+   writeln!(out, "v2.0 raw")?;
+
+   let mut i = 0;
+   while i < words.len() {
+      let value = words[i];
+      let mut run = 1;
+      while i + run < words.len() && words[i + run] == value {
+         run += 1;
+      }
+
+      if run > 1 {
+         writeln!(out, "{}*{:x}", run, value)?;
+      } else {
+         writeln!(out, "{:x}", value)?;
+      }
+
+      i += run;
+   }
+
+   Ok(())
+}
+
+fn write_words<W: io::Write>(format: OutputFormat, words: &[u16], out: &mut W) -> io::Result<()> {
+   match format {
+      OutputFormat::Listing => unreachable!(),
+      OutputFormat::Binary { little_endian } => write_binary(words, little_endian, out),
+      OutputFormat::IntelHex => write_intel_hex(words, out),
+      OutputFormat::Logisim => write_logisim(words, out),
+   }
+}
+
+fn parse_file(name: Option<String>, format: OutputFormat, auto_pool: bool, mut out: Box<io::Write>) -> bool {
    let mut symbols = SymbolTable::new();
-   let parsed;
+   let mut address = 0u16;
+   let mut lines = Vec::<Line>::new();
+   let mut included = HashSet::<PathBuf>::new();
+
    let file_name: &str;
+   let success;
    if let Some(ref s) = name {
       file_name = s;
+      if let Ok(canon) = Path::new(s).canonicalize() {
+         included.insert(canon);
+      }
       let f = File::open(s).unwrap();
-      parsed = parse_lines(file_name, BufReader::new(f), &mut symbols);
+      let dir = Path::new(s).parent().unwrap_or(Path::new("")).to_path_buf();
+      success = parse_lines(file_name, BufReader::new(f), &dir, &mut symbols, &mut address, &mut lines, &mut included);
    } else {
       file_name = "<stdin>";
       let stdin = io::stdin();
-      parsed = parse_lines(file_name, stdin.lock(), &mut symbols);
+      success = parse_lines(file_name, stdin.lock(), Path::new(""), &mut symbols, &mut address, &mut lines, &mut included);
    }
 
-   match parsed {
-      Some(lines) => assemble_file(file_name, &lines, &symbols),
-      None => false,
+   if !success {
+      return false;
    }
+
+   if auto_pool {
+      lines = match apply_auto_pool(lines, &mut symbols) {
+         Ok(lines) => lines,
+         Err(diagnostics) => {
+            for d in &diagnostics {
+               d.print(file_name);
+            }
+            return false;
+         }
+      };
+   }
+
+   if let OutputFormat::Listing = format {
+      return assemble_file(file_name, &lines, &symbols, &mut out);
+   }
+
+   match assemble_words(&lines, &symbols) {
+      Ok(words) => write_words(format, &words, &mut out).unwrap(),
+      Err(diagnostics) => {
+         for d in &diagnostics {
+            d.print(file_name);
+         }
+         return false;
+      }
+   }
+
+   true
 }
 
 fn main() {
+   let mut file_name: Option<String> = None;
+   let mut format = OutputFormat::Listing;
+   let mut out_path: Option<String> = None;
+   let mut auto_pool = false;
+
    let mut args = std::env::args().skip(1);
-   let exit_code = if parse_file(args.next()) { 0 } else { 1 };
+   while let Some(arg) = args.next() {
+      match arg.as_str() {
+         "--format" => {
+            let value = args.next().unwrap_or_else(|| {
+               eprintln!("Error: --format requires a value.");
+               std::process::exit(1);
+            });
+            format = format_from_string(&value).unwrap_or_else(|| {
+               eprintln!("Error: unknown output format '{}'.", value);
+               std::process::exit(1);
+            });
+         },
+         "-o" => {
+            out_path = Some(args.next().unwrap_or_else(|| {
+               eprintln!("Error: -o requires a value.");
+               std::process::exit(1);
+            }));
+         },
+         "--auto-pool" => auto_pool = true,
+         _ => file_name = Some(arg),
+      }
+   }
+
+   let out: Box<io::Write> = match out_path {
+      Some(ref p) => Box::new(File::create(p).unwrap()),
+      None => Box::new(io::stdout()),
+   };
+
+   let exit_code = if parse_file(file_name, format, auto_pool, out) { 0 } else { 1 };
    std::process::exit(exit_code);
 }
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::io::Cursor;
+
+   fn eval(text: &str) -> u16 {
+      let mut symbols = SymbolTable::new();
+      let expr = parse_expr(text, &mut symbols).unwrap();
+      eval_expr(&expr, &symbols).unwrap()
+   }
+
+   #[test]
+   fn expr_operator_precedence() {
+      assert_eq!(eval("2+3*4"), 14);
+      assert_eq!(eval("(2+3)*4"), 20);
+      assert_eq!(eval("1<<2+1"), 8);
+      assert_eq!(eval("6&3|8"), 10);
+   }
+
+   #[test]
+   fn expr_unary_and_wrapping() {
+      assert_eq!(eval("~0"), 0xffff);
+      assert_eq!(eval("0-1"), 0xffff);
+      assert_eq!(eval("-1"), 0xffff);
+      assert_eq!(eval("$ffff+1"), 0);
+   }
+
+   #[test]
+   fn expr_division_by_zero_yields_zero() {
+      assert_eq!(eval("5/0"), 0);
+   }
+
+   // Assembles `src` with auto-pool enabled and returns the resulting words,
+   // exercising layout_auto_pool/apply_auto_pool's iterate-to-fixed-point pass
+   // end to end rather than poking at its internals directly.
+   fn assemble_auto_pool(src: &str) -> Vec<u16> {
+      let mut symbols = SymbolTable::new();
+      let mut address = 0u16;
+      let mut lines = Vec::<Line>::new();
+      let mut included = HashSet::<PathBuf>::new();
+      let ok = parse_lines("<test>", Cursor::new(src), Path::new(""), &mut symbols, &mut address,
+                            &mut lines, &mut included);
+      assert!(ok, "parse failed for: {}", src);
+      let lines = apply_auto_pool(lines, &mut symbols).unwrap_or_else(|_| panic!("auto-pool failed for: {}", src));
+      assemble_words(&lines, &symbols).unwrap_or_else(|_| panic!("assemble failed for: {}", src))
+   }
+
+   #[test]
+   fn auto_pool_rewrites_page_spanning_reference() {
+      // TARGET lives on page 1 (@0100..@0177); LDA at @0000 can't reach it
+      // directly and needs a pool word on page 0 to indirect through.
+      let words = assemble_auto_pool("LDA TARGET\nORG @100\nTARGET: NOP\n");
+      assert_eq!(words.len(), 0o101);
+      // the pool word (WRD @0100) must sit before the page boundary at @0100
+      assert!(words[1..0o100].contains(&0o100));
+   }
+
+   #[test]
+   fn auto_pool_is_stable_once_no_line_needs_pooling() {
+      let words = assemble_auto_pool("LDA TARGET\nTARGET: NOP\n");
+      assert_eq!(words.len(), 2);
+   }
+}